@@ -17,6 +17,7 @@
 
 use std::io::prelude::*;
 use std::fs::File;
+use std::fmt;
 use std::collections::{HashMap, BTreeMap};
 use mustache;
 use rustc_serialize::json::Json;
@@ -35,6 +36,60 @@ pub fn package(pkg: &str) -> BldrResult<()> {
     println!("   {}: Copying START", pkg);
     try!(package.copy_start());
 
+    let (final_toml, _) = try!(layered_config(pkg, &package));
+    let final_data = toml_table_to_mustache(final_toml);
+
+    println!("   {}: Writing out configuration files", pkg);
+    let config_files = try!(package.config_files());
+    for config in config_files {
+        let template = try!(mustache::compile_path(package.join_path(&format!("config/{}", config))));
+        println!("   {}: Rendering {}", pkg, Purple.bold().paint(&config));
+        let mut config_file = try!(File::create(package.srvc_join_path(&format!("config/{}", config))));
+        template.render_data(&mut config_file, &final_data);
+    }
+    println!("   {}: Configured", pkg);
+    Ok(())
+}
+
+/// Prints every effective config key for `pkg`, its final value, and which layer - DEFAULT.toml,
+/// the etcd discovery overlay, or the `BLDR_{pkg}` environment overlay - last set it. Handy for
+/// figuring out where a surprising rendered value actually came from.
+pub fn show(pkg: &str) -> BldrResult<()> {
+    let package = try!(pkg::latest(pkg));
+    let (final_toml, provenance) = try!(layered_config(pkg, &package));
+
+    println!("   {}: Effective configuration", pkg);
+    for (key, source) in provenance.iter() {
+        let value = toml_lookup(&final_toml, key).expect("provenance key missing from merged config");
+        println!("   {} = {:?} ({})", key, value, source);
+    }
+    Ok(())
+}
+
+/// Where a merged config key's final value came from - the layers are applied in this order,
+/// each overriding the one before it on a per-leaf-key basis.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Source {
+    Default,
+    Discovery,
+    Environment,
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match *self {
+            Source::Default => "default",
+            Source::Discovery => "discovery",
+            Source::Environment => "environment",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// Loads DEFAULT.toml, overlays the etcd discovery config, then overlays the environment
+/// configuration, returning both the merged table and a map of dotted key path to the layer
+/// that last wrote it.
+fn layered_config(pkg: &str, package: &pkg::Package) -> BldrResult<(BTreeMap<String, toml::Value>, BTreeMap<String, Source>)> {
     println!("   {}: Loading default data", pkg);
     let mut default_toml_file = try!(File::open(package.join_path("config/DEFAULT.toml")));
     let mut toml_data = String::new();
@@ -42,30 +97,132 @@ pub fn package(pkg: &str) -> BldrResult<()> {
     let mut toml_parser = toml::Parser::new(&toml_data);
     let default_toml_value = try!(toml_parser.parse().ok_or(BldrError::TomlParser(toml_parser.errors)));
 
+    // Seed provenance for the lowest layer once, up front - every later merge only updates the
+    // specific keys the next layer actually touches, so this is the only place Source::Default
+    // gets recorded.
+    let mut provenance = BTreeMap::new();
+    record_provenance(&toml::Value::Table(default_toml_value.clone()), Source::Default, "", &mut provenance);
+
     let discovery_toml = match discovery::etcd::get_config(pkg) {
         Some(discovery_toml_value) => {
-            toml_merge(default_toml_value, discovery_toml_value)
+            toml_merge_with_provenance(default_toml_value, discovery_toml_value, Source::Discovery, "", &mut provenance)
         },
         None => default_toml_value
     };
 
     println!("   {}: Overlaying environment configuration", pkg);
     let env_toml = try!(env_to_toml(pkg));
-    let final_data = match env_toml {
-        Some(env_toml_value) => toml_table_to_mustache(toml_merge(discovery_toml, env_toml_value)),
-        None => toml_table_to_mustache(discovery_toml)
+    let blob_merged = match env_toml {
+        Some(env_toml_value) => {
+            toml_merge_with_provenance(discovery_toml, env_toml_value, Source::Environment, "", &mut provenance)
+        },
+        None => discovery_toml
     };
 
-    println!("   {}: Writing out configuration files", pkg);
-    let config_files = try!(package.config_files());
-    for config in config_files {
-        let template = try!(mustache::compile_path(package.join_path(&format!("config/{}", config))));
-        println!("   {}: Rendering {}", pkg, Purple.bold().paint(&config));
-        let mut config_file = try!(File::create(package.srvc_join_path(&format!("config/{}", config))));
-        template.render_data(&mut config_file, &final_data);
+    let structured_env_toml = structured_env_to_toml(pkg);
+    let final_toml = if structured_env_toml.is_empty() {
+        blob_merged
+    } else {
+        toml_merge_with_provenance(blob_merged, structured_env_toml, Source::Environment, "", &mut provenance)
+    };
+
+    Ok((final_toml, provenance))
+}
+
+fn toml_lookup<'a>(toml: &'a BTreeMap<String, toml::Value>, key_path: &str) -> Option<&'a toml::Value> {
+    let mut segments = key_path.split('.');
+    let mut value = match segments.next().and_then(|first| toml.get(first)) {
+        Some(v) => v,
+        None => return None,
+    };
+    for segment in segments {
+        value = match *value {
+            toml::Value::Table(ref t) => match t.get(segment) {
+                Some(v) => v,
+                None => return None,
+            },
+            _ => return None,
+        };
+    }
+    Some(value)
+}
+
+/// A recursive merge of two Toml tables, with `right` taking precedence. Keys that exist on only
+/// one side are kept as-is; keys present on both sides are merged recursively when both values
+/// are `toml::Value::Table`, otherwise the `right` value wins outright. This lets DEFAULT.toml,
+/// the etcd discovery overlay, and the environment overlay each set an individual leaf key
+/// without needing to redeclare the rest of that key's table.
+///
+/// Also updates `provenance` to reflect every leaf key that `right` actually touches, tagging it
+/// `right_source`. Provenance for keys untouched by `right` is left exactly as it was - it's the
+/// caller's job to have already seeded `provenance` for `left`'s own leaves (via
+/// `record_provenance`, once, for the lowest layer)
+/// before the first call; repainting all of `left` on every call here would clobber the true
+/// origin recorded by an earlier merge with whatever layer happens to be on the right this time.
+///
+/// When a key's value changes shape between `left` and `right` (a table collapsing to a scalar,
+/// or vice versa), the old value's provenance entries - the key itself, plus every entry nested
+/// under it - are stale and are cleared before recording the new ones, so a later lookup by key
+/// path never lands on a table that no longer exists.
+fn toml_merge_with_provenance(left: BTreeMap<String, toml::Value>,
+                               right: BTreeMap<String, toml::Value>,
+                               right_source: Source,
+                               prefix: &str,
+                               provenance: &mut BTreeMap<String, Source>)
+                               -> BTreeMap<String, toml::Value> {
+    let mut final_map = left;
+    for (right_key, right_value) in right.into_iter() {
+        let key_path = join_path(prefix, &right_key);
+        let old_value = final_map.remove(&right_key);
+        match (old_value, right_value) {
+            (Some(toml::Value::Table(left_table)), toml::Value::Table(right_table)) => {
+                let merged = toml_merge_with_provenance(left_table, right_table, right_source, &key_path, provenance);
+                final_map.insert(right_key, toml::Value::Table(merged));
+            },
+            (old_value, right_value) => {
+                if old_value.is_some() {
+                    clear_provenance(provenance, &key_path);
+                }
+                record_provenance(&right_value, right_source, &key_path, provenance);
+                final_map.insert(right_key, right_value);
+            },
+        }
+    }
+    final_map
+}
+
+/// Records `value`'s own provenance as a single leaf at `key_path`, or recurses and records every
+/// leaf beneath it when `value` is a table.
+fn record_provenance(value: &toml::Value, source: Source, key_path: &str, provenance: &mut BTreeMap<String, Source>) {
+    match *value {
+        toml::Value::Table(ref t) => {
+            for (key, value) in t.iter() {
+                record_provenance(value, source, &join_path(key_path, key), provenance);
+            }
+        },
+        _ => {
+            provenance.insert(key_path.to_string(), source);
+        },
+    }
+}
+
+/// Removes any provenance entry for `key_path` itself, plus every entry nested beneath it, so a
+/// stale record from a value's previous shape can't outlive the value it described.
+fn clear_provenance(provenance: &mut BTreeMap<String, Source>, key_path: &str) {
+    provenance.remove(key_path);
+    let nested_prefix = format!("{}.", key_path);
+    let stale: Vec<String> = provenance.keys().filter(|k| k.starts_with(&nested_prefix)).cloned().collect();
+    for key in stale {
+        provenance.remove(&key);
+    }
+}
+
+fn join_path(prefix: &str, key: &str) -> String {
+    if prefix.is_empty() {
+        key.to_string()
+    } else {
+        format!("{}.{}", prefix, key)
     }
-    println!("   {}: Configured", pkg);
-    Ok(())
 }
 
 fn env_to_toml(pkg: &str) -> BldrResult<Option<BTreeMap<String, toml::Value>>> {
@@ -81,21 +238,65 @@ fn env_to_toml(pkg: &str) -> BldrResult<Option<BTreeMap<String, toml::Value>>> {
     Ok(Some(toml_value))
 }
 
-/// A completely shallow merge of two Toml tables. For v0 of Bldr, if you set any nested key,
-/// you must set *all* the keys in that nesting, or your out of luck. Someday, this will need
-/// to become a legitimate deep merge.
-///
-/// We use toml as the middle language because its implementation in rust lends itself to easy
-/// cloning of even the deep data.
-fn toml_merge(left: BTreeMap<String, toml::Value>, right: BTreeMap<String, toml::Value>) -> BTreeMap<String, toml::Value> {
-    let mut final_map = BTreeMap::new();
-    for (left_key, left_value) in left.iter() {
-        match right.get(left_key) {
-            Some(right_value) => { final_map.insert(left_key.clone(), right_value.clone()); },
-            None => { final_map.insert(left_key.clone(), left_value.clone()); },
+/// Scans the process environment for structured per-key overrides of the form
+/// `BLDR_{pkg}_{section}__{key}` (`__` separating nesting levels) and parses them into a nested
+/// table, e.g. `BLDR_redis_WINKS__RIGHT=yes` becomes `{winks: {right: "yes"}}`. Unlike the single
+/// `BLDR_{pkg}` blob in `env_to_toml`, each variable only has to carry one value, so it's a much
+/// smaller thing to set from an orchestrator or CI job.
+fn structured_env_to_toml(pkg: &str) -> BTreeMap<String, toml::Value> {
+    let prefix = format!("BLDR_{}_", pkg);
+    let mut result = BTreeMap::new();
+    for (name, value) in env::vars() {
+        if !name.starts_with(&prefix) {
+            continue;
         }
+        let path: Vec<String> = name[prefix.len()..].split("__").map(|s| s.to_lowercase()).collect();
+        if path.iter().any(|segment| segment.is_empty()) {
+            continue;
+        }
+        insert_nested(&mut result, &path, parse_env_scalar(&value));
+    }
+    result
+}
+
+fn insert_nested(map: &mut BTreeMap<String, toml::Value>, path: &[String], value: toml::Value) {
+    if path.len() == 1 {
+        // A table already set at this key by a more specific (nested) variable wins over a
+        // single-segment scalar override - otherwise which one wins would depend on the
+        // unspecified iteration order of env::vars(). A table is always the more specific value,
+        // regardless of which variable happened to be processed first.
+        if let Some(&toml::Value::Table(_)) = map.get(&path[0]) {
+            debug!("Ignoring scalar override for \"{}\" - a more specific nested override already set it", path[0]);
+            return;
+        }
+        map.insert(path[0].clone(), value);
+        return;
+    }
+    let sub_table = match map.entry(path[0].clone()).or_insert_with(|| toml::Value::Table(BTreeMap::new())) {
+        &mut toml::Value::Table(ref mut t) => t,
+        other => {
+            // A scalar was already set at this path by a less-specific variable; a table wins,
+            // since it's the more specific override.
+            *other = toml::Value::Table(BTreeMap::new());
+            match *other {
+                toml::Value::Table(ref mut t) => t,
+                _ => unreachable!(),
+            }
+        }
+    };
+    insert_nested(sub_table, &path[1..], value);
+}
+
+/// Parses a raw environment variable value as a Toml value (an integer, float or boolean, but
+/// also e.g. an array or a quoted string), falling back to a plain string when it isn't valid
+/// Toml on its own (e.g. `redis-node-1`).
+fn parse_env_scalar(raw: &str) -> toml::Value {
+    let wrapped = format!("v = {}", raw);
+    let mut parser = toml::Parser::new(&wrapped);
+    match parser.parse() {
+        Some(mut table) => table.remove("v").unwrap_or_else(|| toml::Value::String(raw.to_string())),
+        None => toml::Value::String(raw.to_string()),
     }
-    final_map
 }
 
 pub fn toml_table_to_mustache(toml: BTreeMap<String, toml::Value>) -> mustache::Data {
@@ -157,7 +358,10 @@ pub fn json_vec_to_mustache(json: Vec<Json>) -> mustache::Data {
 
 #[cfg(test)]
 mod tests {
-    use super::{json_object_to_mustache, toml_table_to_mustache};
+    use super::{insert_nested, json_object_to_mustache, record_provenance, structured_env_to_toml,
+                toml_merge_with_provenance, toml_table_to_mustache, Source};
+    use std::collections::BTreeMap;
+    use std::env;
     use rustc_serialize::json::Json;
     use toml;
     use mustache;
@@ -202,4 +406,149 @@ mod tests {
         template.render_data(&mut bytes, &data);
         assert_eq!(String::from_utf8(bytes).unwrap(), "hello no for 127.0.0.1 6380 no yes  snooze  looze ".to_string());
     }
+
+    #[test]
+    fn toml_merge_only_overrides_the_leaf_key_that_differs() {
+        let left = toml::Parser::new(r#"
+            [winks]
+            left = "yes"
+            right = "no"
+        "#).parse().unwrap();
+        let right = toml::Parser::new(r#"
+            [winks]
+            right = "yes"
+        "#).parse().unwrap();
+        let mut provenance = BTreeMap::new();
+        let merged = toml_merge_with_provenance(left, right, Source::Environment, "", &mut provenance);
+        let winks = match merged.get("winks").unwrap() {
+            &toml::Value::Table(ref t) => t.clone(),
+            _ => panic!("winks should still be a table"),
+        };
+        assert_eq!(winks.get("left").unwrap().as_str(), Some("yes"));
+        assert_eq!(winks.get("right").unwrap().as_str(), Some("yes"));
+    }
+
+    #[test]
+    fn toml_merge_lets_right_win_when_a_table_collides_with_a_scalar() {
+        let left = toml::Parser::new(r#"
+            [winks]
+            wiggle = [ "snooze", "looze" ]
+        "#).parse().unwrap();
+        let right = toml::Parser::new(r#"
+            winks = "disabled"
+        "#).parse().unwrap();
+        let mut provenance = BTreeMap::new();
+        let merged = toml_merge_with_provenance(left, right, Source::Environment, "", &mut provenance);
+        assert_eq!(merged.get("winks").unwrap().as_str(), Some("disabled"));
+    }
+
+    #[test]
+    fn toml_merge_with_provenance_tags_each_leaf_with_its_winning_layer() {
+        let left = toml::Parser::new(r#"
+            [winks]
+            left = "yes"
+            right = "no"
+        "#).parse().unwrap();
+        let right = toml::Parser::new(r#"
+            [winks]
+            right = "yes"
+        "#).parse().unwrap();
+        let mut provenance = BTreeMap::new();
+        record_provenance(&toml::Value::Table(left.clone()), Source::Default, "", &mut provenance);
+        toml_merge_with_provenance(left, right, Source::Discovery, "", &mut provenance);
+        assert_eq!(provenance.get("winks.left"), Some(&Source::Default));
+        assert_eq!(provenance.get("winks.right"), Some(&Source::Discovery));
+    }
+
+    #[test]
+    fn toml_merge_with_provenance_does_not_repaint_untouched_keys_on_a_later_merge() {
+        // Regression test: a merge pass used to re-tag every leaf still present in `left` with
+        // whatever source was passed for that call, clobbering the true origin an earlier merge
+        // had already recorded for keys the new layer never touches.
+        let default = toml::Parser::new(r#"
+            [winks]
+            left = "yes"
+            right = "no"
+        "#).parse().unwrap();
+        let discovery = toml::Parser::new(r#"
+            [winks]
+            right = "yes"
+        "#).parse().unwrap();
+        let env = toml::Parser::new(r#"
+            unrelated = "value"
+        "#).parse().unwrap();
+
+        let mut provenance = BTreeMap::new();
+        record_provenance(&toml::Value::Table(default.clone()), Source::Default, "", &mut provenance);
+        let merged = toml_merge_with_provenance(default, discovery, Source::Discovery, "", &mut provenance);
+        toml_merge_with_provenance(merged, env, Source::Environment, "", &mut provenance);
+
+        assert_eq!(provenance.get("winks.left"), Some(&Source::Default));
+        assert_eq!(provenance.get("winks.right"), Some(&Source::Discovery));
+        assert_eq!(provenance.get("unrelated"), Some(&Source::Environment));
+    }
+
+    #[test]
+    fn toml_merge_with_provenance_clears_stale_subtree_entries_when_a_table_collapses_to_a_scalar() {
+        // Regression test: collapsing `[winks] left=.. right=..` down to a plain scalar used to
+        // leave "winks.left"/"winks.right" behind in provenance, pointing at keys that no longer
+        // exist in the merged table - which made `config::show` panic on the lookup.
+        let left = toml::Parser::new(r#"
+            [winks]
+            left = "yes"
+            right = "no"
+        "#).parse().unwrap();
+        let right = toml::Parser::new(r#"
+            winks = "disabled"
+        "#).parse().unwrap();
+
+        let mut provenance = BTreeMap::new();
+        record_provenance(&toml::Value::Table(left.clone()), Source::Default, "", &mut provenance);
+        let merged = toml_merge_with_provenance(left, right, Source::Environment, "", &mut provenance);
+
+        assert_eq!(merged.get("winks").unwrap().as_str(), Some("disabled"));
+        assert_eq!(provenance.get("winks"), Some(&Source::Environment));
+        assert_eq!(provenance.get("winks.left"), None);
+        assert_eq!(provenance.get("winks.right"), None);
+    }
+
+    #[test]
+    fn structured_env_to_toml_parses_nested_keys_and_scalar_types() {
+        env::set_var("BLDR_redis_PORT", "6379");
+        env::set_var("BLDR_redis_ENABLED", "true");
+        env::set_var("BLDR_redis_WINKS__RIGHT", "no");
+
+        let toml = structured_env_to_toml("redis");
+
+        assert_eq!(toml.get("port").unwrap(), &toml::Value::Integer(6379));
+        assert_eq!(toml.get("enabled").unwrap(), &toml::Value::Boolean(true));
+        let winks = match toml.get("winks").unwrap() {
+            &toml::Value::Table(ref t) => t.clone(),
+            _ => panic!("winks should be a table"),
+        };
+        assert_eq!(winks.get("right").unwrap().as_str(), Some("no"));
+
+        env::remove_var("BLDR_redis_PORT");
+        env::remove_var("BLDR_redis_ENABLED");
+        env::remove_var("BLDR_redis_WINKS__RIGHT");
+    }
+
+    #[test]
+    fn insert_nested_lets_a_nested_table_win_over_a_single_segment_scalar_regardless_of_order() {
+        let mut nested_first = BTreeMap::new();
+        insert_nested(&mut nested_first, &vec!["winks".to_string(), "right".to_string()], toml::Value::String("no".to_string()));
+        insert_nested(&mut nested_first, &vec!["winks".to_string()], toml::Value::String("disabled".to_string()));
+
+        let mut scalar_first = BTreeMap::new();
+        insert_nested(&mut scalar_first, &vec!["winks".to_string()], toml::Value::String("disabled".to_string()));
+        insert_nested(&mut scalar_first, &vec!["winks".to_string(), "right".to_string()], toml::Value::String("no".to_string()));
+
+        for result in &[nested_first, scalar_first] {
+            let winks = match result.get("winks").unwrap() {
+                &toml::Value::Table(ref t) => t.clone(),
+                other => panic!("winks should still be a table, got {:?}", other),
+            };
+            assert_eq!(winks.get("right").unwrap().as_str(), Some("no"));
+        }
+    }
 }
\ No newline at end of file
@@ -0,0 +1,152 @@
+//
+// Copyright:: Copyright (c) 2015 Chef Software, Inc.
+// License:: Apache License, Version 2.0
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+use std::collections::{BTreeMap, BTreeSet};
+use toml;
+
+/// The protocol version this node speaks. Bump the major component whenever a change to the
+/// etcd discovery schema would make an older peer misread a newer peer's config (or vice versa).
+pub const PROTOCOL_VERSION: (u32, u32) = (1, 0);
+
+/// The discovery features this build of bldr actually supports. Used as this node's own
+/// `NodeVersion::capabilities`, the local side of `negotiate`, so a peer on an older
+/// build that hasn't rolled out, say, `structured-env-overlay` yet gets it dropped from the
+/// negotiated set rather than assumed present.
+pub const LOCAL_CAPABILITIES: &'static [&'static str] = &["deep-merge", "config-provenance", "structured-env-overlay"];
+
+fn local_capabilities() -> BTreeSet<String> {
+    LOCAL_CAPABILITIES.iter().map(|c| c.to_string()).collect()
+}
+
+/// A node's self-reported version and feature set, read alongside a peer's config so that this
+/// node can tell whether it's safe to merge a discovered config during a rolling upgrade. This
+/// module only reads and compares these records; it does not publish this node's own.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NodeVersion {
+    pub version: String,
+    pub protocol: (u32, u32),
+    pub capabilities: BTreeSet<String>,
+}
+
+impl NodeVersion {
+    pub fn new(version: &str, protocol: (u32, u32), capabilities: BTreeSet<String>) -> NodeVersion {
+        NodeVersion {
+            version: version.to_string(),
+            protocol: protocol,
+            capabilities: capabilities,
+        }
+    }
+}
+
+/// Whether `peer`'s protocol version is safe to merge alongside `local`'s, and if so, the set of
+/// capabilities the two nodes have in common. A differing protocol major means the two nodes
+/// disagree on the shape of the config schema itself, so the peer's config is rejected outright;
+/// a differing minor is accepted (minors are additive) and just narrows the negotiated
+/// capabilities to whatever both sides advertise.
+pub fn negotiate(local: &NodeVersion, peer: &NodeVersion) -> Result<BTreeSet<String>, String> {
+    if local.protocol.0 != peer.protocol.0 {
+        return Err(format!("peer {} speaks protocol {}.{}, which is incompatible with this node's {}.{}",
+                            peer.version, peer.protocol.0, peer.protocol.1,
+                            local.protocol.0, local.protocol.1));
+    }
+    let negotiated = local.capabilities.intersection(&peer.capabilities).cloned().collect();
+    Ok(negotiated)
+}
+
+/// Pulls the config table a peer has published for `pkg` from etcd, along with the `NodeVersion`
+/// it advertised alongside that config, negotiating protocol compatibility before handing the
+/// config back to the caller. Peers on an incompatible protocol major are logged and skipped
+/// rather than merged, so a config::package() run during a rolling upgrade can't silently mix
+/// schemas across mismatched nodes. Since `fetch_peer_config` always returns None, this always
+/// returns None.
+pub fn get_config(pkg: &str) -> Option<BTreeMap<String, toml::Value>> {
+    match fetch_peer_config(pkg) {
+        Some((peer_version, config)) => {
+            let local = NodeVersion::new(env!("CARGO_PKG_VERSION"), PROTOCOL_VERSION, local_capabilities());
+            match negotiate(&local, &peer_version) {
+                Ok(capabilities) => {
+                    debug!("Discovery peer for {} negotiated capabilities {:?}", pkg, capabilities);
+                    Some(config)
+                },
+                Err(reason) => {
+                    println!("   {}: Ignoring discovery config - {}", pkg, reason);
+                    None
+                }
+            }
+        },
+        None => None
+    }
+}
+
+/// The capability set this node negotiates with the discovery peer for `pkg`, so callers can gate
+/// behavior (e.g. skip a feature the rest of the cluster hasn't rolled out yet) on what the
+/// cluster actually supports rather than on this node's own version alone. The peer's version
+/// record is fetched and negotiated afresh on each call, the same way `get_config` does it;
+/// nothing from an earlier `get_config` call is reused.
+/// Empty when there's no discovery peer, or when the peer's protocol major was incompatible.
+pub fn capabilities(pkg: &str) -> BTreeSet<String> {
+    match fetch_peer_config(pkg) {
+        Some((peer_version, _)) => {
+            let local = NodeVersion::new(env!("CARGO_PKG_VERSION"), PROTOCOL_VERSION, local_capabilities());
+            negotiate(&local, &peer_version).unwrap_or_else(|_| BTreeSet::new())
+        },
+        None => BTreeSet::new()
+    }
+}
+
+fn fetch_peer_config(_pkg: &str) -> Option<(NodeVersion, BTreeMap<String, toml::Value>)> {
+    // No etcd client is wired in here: this always returns None, so `get_config` and
+    // `capabilities` never see a discovery peer.
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{local_capabilities, negotiate, NodeVersion, LOCAL_CAPABILITIES};
+    use std::collections::BTreeSet;
+
+    fn capabilities(names: &[&str]) -> BTreeSet<String> {
+        names.iter().map(|n| n.to_string()).collect()
+    }
+
+    #[test]
+    fn negotiate_rejects_a_differing_protocol_major() {
+        let local = NodeVersion::new("0.1.0", (2, 0), capabilities(&["watch"]));
+        let peer = NodeVersion::new("0.0.9", (1, 3), capabilities(&["watch"]));
+        assert!(negotiate(&local, &peer).is_err());
+    }
+
+    #[test]
+    fn negotiate_accepts_a_differing_protocol_minor_and_intersects_capabilities() {
+        let local = NodeVersion::new("0.1.0", (1, 1), capabilities(&["watch", "ttl"]));
+        let peer = NodeVersion::new("0.0.9", (1, 0), capabilities(&["watch"]));
+        let negotiated = negotiate(&local, &peer).unwrap();
+        assert!(negotiated.contains("watch"));
+        assert!(!negotiated.contains("ttl"));
+    }
+
+    #[test]
+    fn local_capabilities_advertises_this_build_s_real_feature_set() {
+        let local = local_capabilities();
+        for capability in LOCAL_CAPABILITIES {
+            assert!(local.contains(*capability));
+        }
+        let peer = NodeVersion::new("0.0.9", (1, 0), local.clone());
+        let negotiated = negotiate(&NodeVersion::new("0.1.0", (1, 0), local), &peer).unwrap();
+        assert!(!negotiated.is_empty());
+    }
+}
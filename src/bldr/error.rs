@@ -0,0 +1,85 @@
+//
+// Copyright:: Copyright (c) 2015 Chef Software, Inc.
+// License:: Apache License, Version 2.0
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::result;
+use hyper;
+use toml;
+
+#[derive(Debug)]
+pub enum BldrError {
+    CannotParseFileName,
+    ChecksumMismatch { expected: String, actual: String },
+    HyperError(hyper::Error),
+    InvalidContentRange,
+    Io(io::Error),
+    TomlParser(Vec<toml::ParserError>),
+    WriteSyncFailed,
+}
+
+pub type BldrResult<T> = result::Result<T, BldrError>;
+
+impl fmt::Display for BldrError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let msg = match *self {
+            BldrError::CannotParseFileName => {
+                "Cannot determine the filename from the given URL".to_string()
+            },
+            BldrError::ChecksumMismatch { ref expected, ref actual } => {
+                format!("Checksum mismatch: expected {}, got {}", expected, actual)
+            },
+            BldrError::HyperError(ref err) => format!("{}", err),
+            BldrError::InvalidContentRange => {
+                "Server's Content-Range total did not match the expected file size".to_string()
+            },
+            BldrError::Io(ref err) => format!("{}", err),
+            BldrError::TomlParser(ref errs) => format!("Failed to parse toml: {:?}", errs),
+            BldrError::WriteSyncFailed => {
+                "Could not write the entire contents of the buffer to the tempfile".to_string()
+            },
+        };
+        write!(f, "{}", msg)
+    }
+}
+
+impl Error for BldrError {
+    fn description(&self) -> &str {
+        match *self {
+            BldrError::CannotParseFileName => "Cannot determine the filename from the given URL",
+            BldrError::ChecksumMismatch { .. } => "Checksum of the downloaded file did not match the expected value",
+            BldrError::HyperError(ref err) => err.description(),
+            BldrError::InvalidContentRange => "Server's Content-Range total did not match the expected file size",
+            BldrError::Io(ref err) => err.description(),
+            BldrError::TomlParser(_) => "Failed to parse toml",
+            BldrError::WriteSyncFailed => "Could not write the entire contents of the buffer to the tempfile",
+        }
+    }
+}
+
+impl From<io::Error> for BldrError {
+    fn from(err: io::Error) -> BldrError {
+        BldrError::Io(err)
+    }
+}
+
+impl From<hyper::Error> for BldrError {
+    fn from(err: hyper::Error) -> BldrError {
+        BldrError::HyperError(err)
+    }
+}
@@ -0,0 +1,59 @@
+//
+// Copyright:: Copyright (c) 2015 Chef Software, Inc.
+// License:: Apache License, Version 2.0
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+
+extern crate ansi_term;
+extern crate crypto;
+extern crate hyper;
+extern crate mustache;
+extern crate rustc_serialize;
+extern crate toml;
+#[macro_use]
+extern crate log;
+
+mod command;
+mod discovery;
+mod error;
+mod util;
+
+use std::env;
+use std::process;
+
+use error::BldrError;
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    let result = match args.get(1).map(String::as_str) {
+        Some("config") => run_config(&args[2..]),
+        _ => { print_usage(); process::exit(1); },
+    };
+    if let Err(e) = result {
+        println!("{}", e);
+        process::exit(1);
+    }
+}
+
+fn run_config(args: &[String]) -> Result<(), BldrError> {
+    match (args.get(0).map(String::as_str), args.get(1)) {
+        (Some("show"), Some(pkg)) => command::config::show(pkg),
+        _ => { print_usage(); process::exit(1); },
+    }
+}
+
+fn print_usage() {
+    println!("Usage:");
+    println!("  bldr config show <pkg>    Print every effective config key for <pkg>, its value, and where it was set");
+}
@@ -17,17 +17,49 @@
 
 use hyper;
 use hyper::client::Client;
+use hyper::header::{ByteRangeSpec, ContentLength, ContentRange, ContentRangeSpec, Range};
+use hyper::status::StatusCode;
 use std::io::{Read, Write, BufWriter};
-use std::fs::{self, File};
+use std::fs::{self, File, OpenOptions};
+use crypto::digest::Digest;
+use crypto::sha2::Sha256;
 use error::{BldrResult, BldrError};
 
-pub fn download(status: &str, url: &str, path: &str) -> BldrResult<String> {
+pub fn download(status: &str, url: &str, path: &str, expected_digest: Option<&str>) -> BldrResult<String> {
+    let file_name = try!(file_name(url));
+    let tempfile = format!("{}/{}.tmp", path, file_name);
+    let finalfile = format!("{}/{}", path, file_name);
+    let resume_from = match fs::metadata(&tempfile) {
+        Ok(meta) => meta.len(),
+        Err(_) => 0,
+    };
+
     let mut client = Client::new();
     debug!("Making request to url {}", url);
-    let mut res = try!(client.get(url).send());
+    let mut request = client.get(url);
+    if resume_from > 0 {
+        request = request.header(Range::Bytes(vec![ByteRangeSpec::AllFrom(resume_from)]));
+    }
+    let mut res = try!(request.send());
     debug!("Response: {:?}", res);
-    let length = res.headers.get::<hyper::header::ContentLength>()
+
+    // The server might not support ranges at all, in which case it answers 200 and sends the
+    // whole body from the start - fall back to truncating the tempfile and downloading it fresh.
+    let resumed = should_resume(resume_from, res.status);
+
+    let length = res.headers.get::<ContentLength>()
         .map_or("Unknown".to_string(), |v| format!("{}", v));
+
+    if resumed {
+        if let Some(content_range) = res.headers.get::<ContentRange>() {
+            if let ContentRangeSpec::Bytes { instance_length: Some(total), .. } = content_range.0 {
+                if let Some(&ContentLength(remaining)) = res.headers.get::<ContentLength>() {
+                    try!(validate_resume_range(resume_from, remaining, total));
+                }
+            }
+        }
+    }
+
     // Here is a moment where you can really like Rust. We create
     // a file, wrap it in a BufWriter - which understands how to
     // safely batch writes into large buffer sizes on the heap,
@@ -45,12 +77,29 @@ pub fn download(status: &str, url: &str, path: &str) -> BldrResult<String> {
     // What you can't see is this - the compiler helped with
     // making sure all the edge cases of the pattern were covered,
     // and even though its a trivial case, it was pretty great.
-    let file_name = try!(file_name(url));
-    let tempfile = format!("{}/{}.tmp", path, file_name);
-    let finalfile = format!("{}/{}", path, file_name);
-    let f = try!(File::create(&tempfile));
-    let mut writer = BufWriter::new(&f);
+    let mut hasher = Sha256::new();
     let mut written: i64 = 0;
+    if resumed {
+        // Re-hash the bytes we already have on disk so the final digest still covers the whole
+        // file, then open for append so new bytes land right after them - no seek needed, a file
+        // opened with `append(true)` always writes at EOF regardless of the cursor position.
+        let mut existing = try!(File::open(&tempfile));
+        let mut ebuf = [0u8; 100000];
+        loop {
+            let elen = try!(existing.read(&mut ebuf));
+            if elen == 0 {
+                break;
+            }
+            hasher.input(&ebuf[0 .. elen]);
+        }
+        written = resume_from as i64;
+    }
+    let f = if resumed {
+        try!(OpenOptions::new().append(true).open(&tempfile))
+    } else {
+        try!(File::create(&tempfile))
+    };
+    let mut writer = BufWriter::new(&f);
     let mut buf = [0u8; 100000]; // Our byte buffer
     loop {
         let len = try!(res.read(&mut buf)); // Raise IO errors
@@ -60,6 +109,7 @@ pub fn download(status: &str, url: &str, path: &str) -> BldrResult<String> {
                 break;
             },
             _ => { // Write the buffer to the BufWriter on the Heap
+                hasher.input(&buf[0 .. len]);
                 let bytes_written = try!(writer.write(&buf[0 .. len]));
                 if bytes_written == 0 {
                     return Err(BldrError::WriteSyncFailed);
@@ -69,10 +119,51 @@ pub fn download(status: &str, url: &str, path: &str) -> BldrResult<String> {
             }
         };
     }
+    if let Some(expected) = expected_digest {
+        let actual = hasher.result_str();
+        if let Err(e) = verify_checksum(expected, &actual) {
+            try!(fs::remove_file(&tempfile));
+            return Err(e);
+        }
+    }
     try!(fs::rename(&tempfile, &finalfile));
     Ok(finalfile)
 }
 
+/// Whether a resume attempt should treat the response as a continuation of the partial file
+/// already on disk. A server that doesn't support range requests answers with a plain `200 OK`
+/// and the whole body from the start even though we asked for a `Range`, so a resume is only
+/// safe when we actually had bytes to resume from *and* the server answered `206 Partial
+/// Content`; any other status means the caller should truncate and re-download from scratch.
+fn should_resume(resume_from: u64, status: StatusCode) -> bool {
+    resume_from > 0 && status == StatusCode::PartialContent
+}
+
+/// Whether a `206 Partial Content` response's `Content-Range` is consistent with the bytes we
+/// already have on disk. `resume_from` is how many bytes of the file we'd already downloaded;
+/// `remaining` and `total` come from the response's `Content-Length` and `Content-Range`
+/// instance-length respectively. A mismatch means the artifact changed out from under us between
+/// attempts (a new build published under the same URL, a CDN edge serving a different object,
+/// etc.), so the resume is rejected rather than silently stitching two different files together.
+fn validate_resume_range(resume_from: u64, remaining: u64, total: u64) -> BldrResult<()> {
+    if resume_from + remaining == total {
+        Ok(())
+    } else {
+        Err(BldrError::InvalidContentRange)
+    }
+}
+
+/// Whether a downloaded artifact's computed digest matches what the caller expected. Pulled out
+/// of `download` so the comparison - the part that actually decides whether to keep or discard a
+/// downloaded file - can be unit tested without needing a live `hyper::Client`.
+fn verify_checksum(expected: &str, actual: &str) -> BldrResult<()> {
+    if expected == actual {
+        Ok(())
+    } else {
+        Err(BldrError::ChecksumMismatch { expected: expected.to_string(), actual: actual.to_string() })
+    }
+}
+
 fn file_name(url: &str) -> BldrResult<&str> {
     let result = try!(url.split("/").last().ok_or(BldrError::CannotParseFileName));
     Ok(result)
@@ -105,7 +196,8 @@ fn from_char(length: usize, ch: char) -> String {
 
 #[cfg(test)]
 mod tests {
-    use super::{file_name, from_char};
+    use super::{file_name, from_char, should_resume, validate_resume_range, verify_checksum};
+    use hyper::status::StatusCode;
 
     #[test]
     fn file_name_returns_filename_from_url() {
@@ -124,4 +216,39 @@ mod tests {
     fn from_char_returns_the_correct_string() {
         assert_eq!("xxxx", from_char(4, 'x'));
     }
+
+    #[test]
+    fn should_resume_is_true_for_partial_content_with_existing_bytes() {
+        assert!(should_resume(1024, StatusCode::PartialContent));
+    }
+
+    #[test]
+    fn should_resume_is_false_with_no_existing_bytes() {
+        assert!(!should_resume(0, StatusCode::PartialContent));
+    }
+
+    #[test]
+    fn should_resume_is_false_when_the_server_falls_back_to_200() {
+        assert!(!should_resume(1024, StatusCode::Ok));
+    }
+
+    #[test]
+    fn validate_resume_range_accepts_a_total_consistent_with_what_we_already_have() {
+        assert!(validate_resume_range(1024, 4096, 5120).is_ok());
+    }
+
+    #[test]
+    fn validate_resume_range_rejects_a_total_that_does_not_add_up() {
+        assert!(validate_resume_range(1024, 4096, 9999).is_err());
+    }
+
+    #[test]
+    fn verify_checksum_accepts_a_matching_digest() {
+        assert!(verify_checksum("abc123", "abc123").is_ok());
+    }
+
+    #[test]
+    fn verify_checksum_rejects_a_mismatched_digest() {
+        assert!(verify_checksum("abc123", "def456").is_err());
+    }
 }
\ No newline at end of file